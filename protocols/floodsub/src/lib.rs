@@ -35,9 +35,10 @@ extern crate tokio_io;
 extern crate unsigned_varint;
 
 mod rpc_proto;
+mod time_cache;
 mod topic;
 
-pub use self::topic::{Topic, TopicBuilder, TopicHash};
+pub use self::topic::{Topic, TopicBuildError, TopicBuilder, TopicHash, MAX_TOPIC_HASH_LEN};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use bytes::{Bytes, BytesMut};
@@ -49,17 +50,98 @@ use log::Level;
 use multiaddr::{Protocol, Multiaddr};
 use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use protobuf::Message as ProtobufMessage;
+use protobuf::error::ProtobufError;
 use smallvec::SmallVec;
+use std::error;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::iter;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use time_cache::DuplicateCache;
+use topic::is_valid_topic_hash;
 use tokio_codec::Framed;
 use tokio_io::{AsyncRead, AsyncWrite};
 use unsigned_varint::codec;
 
+/// Configuration for the floodsub system.
+#[derive(Debug, Clone)]
+pub struct FloodSubConfig {
+    /// Maximum size in bytes of a packet sent or received.
+    max_transmit_size: usize,
+    /// Maximum number of messages of a single received packet that are processed.
+    max_messages_per_rpc: usize,
+    /// Duration during which a received message is remembered in order to ignore duplicates.
+    seen_messages_ttl: Duration,
+    /// Maximum number of received messages remembered at the same time.
+    seen_messages_capacity: usize,
+}
+
+impl FloodSubConfig {
+    /// Builds the default configuration.
+    #[inline]
+    pub fn new() -> FloodSubConfig {
+        Default::default()
+    }
+
+    /// Sets the maximum size in bytes of a packet sent or received over a floodsub connection.
+    ///
+    /// Receiving a larger packet is treated as a protocol error and closes the connection.
+    /// Publishing a message that doesn't fit in a packet fails, and subscriptions are split
+    /// between as many packets as necessary.
+    #[inline]
+    pub fn max_transmit_size(&mut self, max: usize) -> &mut Self {
+        self.max_transmit_size = max;
+        self
+    }
+
+    /// Sets the maximum number of messages of a single received packet that are processed. Any
+    /// message beyond this limit is dropped without being dispatched or forwarded.
+    ///
+    /// A limit is necessary in order to prevent a remote from amplifying our processing and
+    /// forwarding costs by stuffing a single packet with a huge number of messages.
+    #[inline]
+    pub fn max_messages_per_rpc(&mut self, max: usize) -> &mut Self {
+        self.max_messages_per_rpc = max;
+        self
+    }
+
+    /// Sets the duration during which we remember a message we have received, in order to ignore
+    /// it if it is received again.
+    #[inline]
+    pub fn seen_messages_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.seen_messages_ttl = ttl;
+        self
+    }
+
+    /// Sets the maximum number of received messages that we remember at the same time. Beyond
+    /// this limit, the oldest messages are forgotten before the TTL has elapsed.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `capacity` is 0.
+    #[inline]
+    pub fn seen_messages_capacity(&mut self, capacity: usize) -> &mut Self {
+        assert_ne!(capacity, 0);
+        self.seen_messages_capacity = capacity;
+        self
+    }
+}
+
+impl Default for FloodSubConfig {
+    #[inline]
+    fn default() -> FloodSubConfig {
+        FloodSubConfig {
+            max_transmit_size: 1024 * 1024,
+            max_messages_per_rpc: 128,
+            seen_messages_ttl: Duration::from_secs(120),
+            seen_messages_capacity: 65536,
+        }
+    }
+}
+
 /// Implementation of the `ConnectionUpgrade` for the floodsub protocol.
 #[derive(Debug, Clone)]
 pub struct FloodSubUpgrade {
@@ -67,18 +149,30 @@ pub struct FloodSubUpgrade {
 }
 
 impl FloodSubUpgrade {
-    /// Builds a new `FloodSubUpgrade`. Also returns a `FloodSubReceiver` that will stream incoming
-    /// messages for the floodsub system.
+    /// Builds a new `FloodSubUpgrade` with the default configuration. Also returns a
+    /// `FloodSubReceiver` that will stream incoming messages for the floodsub system.
+    #[inline]
     pub fn new(my_id: PeerId) -> (FloodSubUpgrade, FloodSubReceiver) {
+        FloodSubUpgrade::with_config(my_id, FloodSubConfig::new())
+    }
+
+    /// Same as `new`, but uses the given configuration.
+    pub fn with_config(
+        my_id: PeerId,
+        config: FloodSubConfig,
+    ) -> (FloodSubUpgrade, FloodSubReceiver) {
         let (output_tx, output_rx) = mpsc::unbounded();
 
+        let received = DuplicateCache::new(config.seen_messages_ttl, config.seen_messages_capacity);
+
         let inner = Arc::new(Inner {
+            config: config,
             peer_id: my_id.into_bytes(),
             output_tx: output_tx,
             remote_connections: RwLock::new(FnvHashMap::default()),
             subscribed_topics: RwLock::new(Vec::new()),
             seq_no: AtomicUsize::new(0),
-            received: Mutex::new(FnvHashSet::default()),
+            received: Mutex::new(received),
         });
 
         let upgrade = FloodSubUpgrade { inner: inner };
@@ -117,35 +211,38 @@ where
             // FIXME: WRONG
             let remote_addr: Multiaddr = "/ip4/127.0.0.1/tcp/5000".parse().unwrap();
 
-            // Whenever a new node connects, we send to it a message containing the topics we are
-            // already subscribed to.
-            let init_msg: Vec<u8> = {
+            // Whenever a new node connects, we send to it the topics we are already subscribed to.
+            let init_msgs = {
                 let subscribed_topics = self.inner.subscribed_topics.read();
-                let mut proto = rpc_proto::RPC::new();
-
-                for topic in subscribed_topics.iter() {
+                let subscriptions = subscribed_topics.iter().map(|topic| {
                     let mut subscription = rpc_proto::RPC_SubOpts::new();
                     subscription.set_subscribe(true);
                     subscription.set_topicid(topic.hash().clone().into_string());
-                    proto.mut_subscriptions().push(subscription);
-                }
+                    subscription
+                });
 
-                proto
-                    .write_to_bytes()
-                    .expect("programmer error: the protobuf message should always be valid")
+                split_subscriptions(subscriptions, self.inner.config.max_transmit_size)
             };
 
+            let mut codec = codec::UviBytes::default();
+            codec.set_max_len(self.inner.config.max_transmit_size);
+
             // Split the socket into writing and reading parts.
-            let (floodsub_sink, floodsub_stream) = Framed::new(socket, codec::UviBytes::default())
+            let (floodsub_sink, floodsub_stream) = Framed::new(socket, codec)
                 .sink_map_err(|err| IoError::new(IoErrorKind::InvalidData, err))
                 .map_err(|err| IoError::new(IoErrorKind::InvalidData, err))
                 .split();
 
             // Build the channel that will be used to communicate outgoing message to this remote.
             let (input_tx, input_rx) = mpsc::unbounded();
-            input_tx
-                .unbounded_send(init_msg.into())
-                .expect("newly-created channel should always be open");
+            for init_msg in init_msgs {
+                let bytes = init_msg
+                    .write_to_bytes()
+                    .expect("programmer error: the protobuf message should always be valid");
+                input_tx
+                    .unbounded_send(bytes.into())
+                    .expect("newly-created channel should always be open");
+            }
             self.inner.remote_connections.write().insert(
                 remote_addr.clone(),
                 RemoteInfo {
@@ -185,7 +282,15 @@ where
                                         Ok(()) => {
                                             future::ok(future::Loop::Continue((floodsub_sink, rest)))
                                         }
-                                        Err(err) => future::err(err),
+                                        Err(err @ PacketError::InvalidTopicId { .. }) => {
+                                            // Only this packet is rejected; the remote can keep
+                                            // using the connection.
+                                            debug!("Rejected packet from {}: {}", remote_addr, err);
+                                            future::ok(future::Loop::Continue((floodsub_sink, rest)))
+                                        }
+                                        Err(err) => {
+                                            future::err(IoError::new(IoErrorKind::InvalidData, err))
+                                        }
                                     };
                                     Box::new(fut) as Box<_>
                                 }
@@ -230,6 +335,9 @@ pub struct FloodSubController {
 }
 
 struct Inner {
+    // Configuration of the floodsub system.
+    config: FloodSubConfig,
+
     // Our local peer ID multihash, to pass as the source.
     peer_id: Vec<u8>,
 
@@ -247,9 +355,9 @@ struct Inner {
     seq_no: AtomicUsize,
 
     // We keep track of the messages we received (in the format `(remote ID, seq_no)`) so that we
-    // don't dispatch the same message twice if we receive it twice on the network.
-    // TODO: the `HashSet` will keep growing indefinitely :-/
-    received: Mutex<FnvHashSet<u64>>,
+    // don't dispatch the same message twice if we receive it twice on the network. Entries are
+    // forgotten after the TTL of the configuration.
+    received: Mutex<DuplicateCache<u64>>,
 }
 
 struct RemoteInfo {
@@ -262,6 +370,7 @@ struct RemoteInfo {
 impl fmt::Debug for Inner {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Inner")
+            .field("config", &self.config)
             .field("peer_id", &self.peer_id)
             .field(
                 "num_remote_connections",
@@ -340,8 +449,6 @@ impl FloodSubController {
         I: IntoIterator<Item = (&'a Topic, bool)>,
         I::IntoIter: Clone,
     {
-        let mut proto = rpc_proto::RPC::new();
-
         let topics = topics.into_iter();
 
         if log_enabled!(Level::Debug) {
@@ -354,12 +461,13 @@ impl FloodSubController {
                         .collect::<Vec<_>>());
         }
 
+        let mut subscriptions = Vec::new();
         let mut subscribed_topics = self.inner.subscribed_topics.write();
         for (topic, subscribe) in topics {
             let mut subscription = rpc_proto::RPC_SubOpts::new();
             subscription.set_subscribe(subscribe);
             subscription.set_topicid(topic.hash().clone().into_string());
-            proto.mut_subscriptions().push(subscription);
+            subscriptions.push(subscription);
 
             if subscribe {
                 subscribed_topics.push(topic.clone());
@@ -368,12 +476,16 @@ impl FloodSubController {
             }
         }
 
-        self.broadcast(proto, |_| true);
+        for proto in split_subscriptions(subscriptions, self.inner.config.max_transmit_size) {
+            self.broadcast(proto, |_| true);
+        }
     }
 
     /// Publishes a message on the network for the specified topic
+    ///
+    /// Returns an error if the message is too large to be sent.
     #[inline]
-    pub fn publish(&self, topic: &Topic, data: Vec<u8>) {
+    pub fn publish(&self, topic: &Topic, data: Vec<u8>) -> Result<(), PublishError> {
         // This function exists for convenience.
         self.publish_many(iter::once(topic), data)
     }
@@ -383,7 +495,9 @@ impl FloodSubController {
     /// Since this results in a single packet sent to the remotes, it is preferable to use this
     /// method when publishing multiple messages at once rather than call `publish` multiple
     /// times.
-    pub fn publish_many<'a, I>(&self, topics: I, data: Vec<u8>)
+    ///
+    /// Returns an error if the message is too large to be sent.
+    pub fn publish_many<'a, I>(&self, topics: I, data: Vec<u8>) -> Result<(), PublishError>
     where
         I: IntoIterator<Item = &'a Topic>,
     {
@@ -393,22 +507,14 @@ impl FloodSubController {
                topics.iter().map(|t| t.hash().clone().into_string()).collect::<Vec<_>>(),
                data.len());
 
-        // Build the `Vec<u8>` containing our sequence number for this message.
-        let seq_no_bytes = {
-            let mut seqno_bytes = Vec::new();
-            let seqn = self.inner.seq_no.fetch_add(1, Ordering::Relaxed);
-            seqno_bytes
-                .write_u64::<BigEndian>(seqn as u64)
-                .expect("writing to a Vec never fails");
-            seqno_bytes
-        };
-
         // TODO: should handle encryption/authentication of the message
 
+        // The sequence number is only assigned once we know that the message can be sent. Since it
+        // is always encoded on 8 bytes, we use a placeholder for checking the size.
         let mut msg = rpc_proto::Message::new();
         msg.set_data(data);
         msg.set_from(self.inner.peer_id.clone());
-        msg.set_seqno(seq_no_bytes.clone());
+        msg.set_seqno(vec![0; 8]);
         msg.set_topicIDs(
             topics
                 .iter()
@@ -419,6 +525,24 @@ impl FloodSubController {
         let mut proto = rpc_proto::RPC::new();
         proto.mut_publish().push(msg);
 
+        let size = proto.compute_size() as usize;
+        let max = self.inner.config.max_transmit_size;
+        if size > max {
+            debug!("Refusing to publish message of {} bytes; max = {}", size, max);
+            return Err(PublishError::MessageTooLarge { size: size, max: max });
+        }
+
+        // Build the `Vec<u8>` containing our sequence number for this message.
+        let seq_no_bytes = {
+            let mut seqno_bytes = Vec::new();
+            let seqn = self.inner.seq_no.fetch_add(1, Ordering::Relaxed);
+            seqno_bytes
+                .write_u64::<BigEndian>(seqn as u64)
+                .expect("writing to a Vec never fails");
+            seqno_bytes
+        };
+        proto.mut_publish()[0].set_seqno(seq_no_bytes.clone());
+
         // Insert into `received` so that we ignore the message if a remote sends it back to us.
         self.inner
             .received
@@ -428,6 +552,8 @@ impl FloodSubController {
         self.broadcast(proto, |r_top| {
             topics.iter().any(|t| r_top.iter().any(|to| to == t.hash()))
         });
+
+        Ok(())
     }
 
     // Internal function that dispatches an `RPC` protobuf struct to all the connected remotes
@@ -479,6 +605,69 @@ impl FloodSubController {
     }
 }
 
+/// Error that can happen when publishing a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishError {
+    /// The encoded message is larger than the maximum size of a packet.
+    MessageTooLarge {
+        /// Size of the encoded message in bytes.
+        size: usize,
+        /// Maximum size allowed in bytes.
+        max: usize,
+    },
+}
+
+impl error::Error for PublishError {}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PublishError::MessageTooLarge { size, max } =>
+                write!(f, "message of {} bytes exceeds the maximum of {} bytes", size, max),
+        }
+    }
+}
+
+/// Error that can happen when processing a packet received from a remote.
+#[derive(Debug)]
+pub enum PacketError {
+    /// The packet isn't a valid protobuf message. The connection is closed.
+    Protobuf(ProtobufError),
+
+    /// The packet contains a topic ID that is empty or longer than `MAX_TOPIC_HASH_LEN`. The
+    /// whole packet is rejected, but the connection stays open.
+    InvalidTopicId {
+        /// Length in bytes of the invalid topic ID.
+        len: usize,
+    },
+}
+
+impl error::Error for PacketError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PacketError::Protobuf(ref err) => Some(err),
+            PacketError::InvalidTopicId { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketError::Protobuf(ref err) => write!(f, "protobuf error: {}", err),
+            PacketError::InvalidTopicId { len } =>
+                write!(f, "invalid topic ID of {} bytes; max = {}", len, MAX_TOPIC_HASH_LEN),
+        }
+    }
+}
+
+impl From<ProtobufError> for PacketError {
+    #[inline]
+    fn from(err: ProtobufError) -> PacketError {
+        PacketError::Protobuf(err)
+    }
+}
+
 /// Implementation of `Stream` that provides messages for the subscribed topics you subscribed to.
 pub struct FloodSubReceiver {
     inner: mpsc::UnboundedReceiver<Message>,
@@ -547,7 +736,7 @@ fn handle_packet_received(
     bytes: BytesMut,
     inner: Arc<Inner>,
     remote_addr: &Multiaddr,
-) -> Result<(), IoError> {
+) -> Result<(), PacketError> {
     trace!("Received packet from {}", remote_addr);
 
     // Parsing attempt.
@@ -559,6 +748,28 @@ fn handle_packet_received(
         }
     };
 
+    // Reject the whole RPC if it contains a malformed topic ID, so that attacker-controlled
+    // strings are never stored or propagated any further.
+    {
+        let sub_topics = input.get_subscriptions().iter().map(|s| s.get_topicid());
+        let msg_topics = input
+            .get_publish()
+            .iter()
+            .flat_map(|m| m.get_topicIDs().iter().map(|t| &t[..]));
+        if let Some(topic) = sub_topics.chain(msg_topics).find(|t| !is_valid_topic_hash(t)) {
+            return Err(PacketError::InvalidTopicId { len: topic.len() });
+        }
+    }
+
+    // Prevent a remote from amplifying our processing costs by stuffing a single packet with a
+    // huge number of messages.
+    let max_messages = inner.config.max_messages_per_rpc;
+    if input.get_publish().len() > max_messages {
+        debug!("Dropping {} messages of packet received from {} because of the per-packet limit",
+               input.get_publish().len() - max_messages, remote_addr);
+        input.mut_publish().truncate(max_messages);
+    }
+
     // Update the topics the remote is subscribed to.
     if !input.get_subscriptions().is_empty() {
         let remote_connec = inner.remote_connections.write();
@@ -577,23 +788,27 @@ fn handle_packet_received(
         }
     }
 
+    // New messages to forward to each of the other remotes. Each remote is sent at most one
+    // packet, containing only the messages for the topics it is subscribed to.
+    let mut forward: FnvHashMap<Multiaddr, rpc_proto::RPC> = FnvHashMap::default();
+
     // Handle the messages coming from the remote.
-    for publish in input.mut_publish().iter_mut() {
-        let from = publish.take_from();
+    for mut publish in input.take_publish().into_iter() {
+        let from = publish.get_from().to_vec();
         // We maintain a list of the messages that have already been
         // processed so that we don't process the same message twice.
         // Each message is identified by the `(from, seqno)` tuple.
         if !inner
             .received
             .lock()
-            .insert(hash((from.clone(), publish.take_seqno())))
+            .insert(hash((from.clone(), publish.get_seqno().to_vec())))
         {
             trace!("Skipping message because we had already received it; payload = {} bytes",
                    publish.get_data().len());
             continue;
         }
 
-        let peer_id = match PeerId::from_bytes(from.clone()) {
+        let peer_id = match PeerId::from_bytes(from) {
             Ok(id) => id,
             Err(err) => {
                 trace!("Parsing PeerId failed: {:?}. Skipping.", err);
@@ -604,9 +819,9 @@ fn handle_packet_received(
         let from: Multiaddr = Protocol::P2p(peer_id.into()).into();
 
         let topics = publish
-            .take_topicIDs()
-            .into_iter()
-            .map(|h| TopicHash::from_raw(h))
+            .get_topicIDs()
+            .iter()
+            .map(|h| TopicHash::from_raw(h.clone()))
             .collect::<Vec<_>>();
 
         trace!("Processing message for topics {:?}; payload = {} bytes",
@@ -615,17 +830,19 @@ fn handle_packet_received(
 
         // TODO: should check encryption/authentication of the message
 
-        // Broadcast the message to all the other remotes.
+        // Select the remotes to broadcast the message to.
         {
             let remote_connections = inner.remote_connections.read();
             for (addr, info) in remote_connections.iter() {
                 let st = info.subscribed_topics.read();
-                if !topics.iter().any(|t| st.contains(t)) {
-                    continue;
+                if topics.iter().any(|t| st.contains(t)) {
+                    // TODO: don't send back to the remote that just sent it
+                    forward
+                        .entry(addr.clone())
+                        .or_insert_with(rpc_proto::RPC::new)
+                        .mut_publish()
+                        .push(publish.clone());
                 }
-                // TODO: don't send back to the remote that just sent it
-                trace!("Broadcasting received message to {}", addr);
-                let _ = info.sender.unbounded_send(bytes.clone());
             }
         }
 
@@ -649,9 +866,60 @@ fn handle_packet_received(
         }
     }
 
+    // Broadcast the new messages to the other remotes.
+    if !forward.is_empty() {
+        let remote_connections = inner.remote_connections.read();
+        for (addr, rpc) in forward {
+            if let Some(info) = remote_connections.get(&addr) {
+                trace!("Broadcasting {} received messages to {}", rpc.get_publish().len(), addr);
+                let bytes = rpc
+                    .write_to_bytes()
+                    .expect("protobuf message is always valid");
+                let _ = info.sender.unbounded_send(bytes.into());
+            }
+        }
+    }
+
     Ok(())
 }
 
+// Groups `subscriptions` into as few `RPC`s as possible, each of them fitting in `max_size`
+// bytes once encoded. Always produces at least one `RPC`, even if there is no subscription.
+fn split_subscriptions<I>(subscriptions: I, max_size: usize) -> Vec<rpc_proto::RPC>
+where
+    I: IntoIterator<Item = rpc_proto::RPC_SubOpts>,
+{
+    let mut packets = Vec::new();
+    let mut current = rpc_proto::RPC::new();
+    let mut current_size = 0;
+
+    for subscription in subscriptions {
+        // Size of the subscription once embedded in an `RPC`: tag, length prefix and content.
+        let len = subscription.compute_size();
+        let size = 1 + protobuf::rt::compute_raw_varint32_size(len) as usize + len as usize;
+        if size > max_size {
+            warn!("Subscription to {:?} doesn't fit in a packet of {} bytes; not sending it",
+                  subscription.get_topicid(), max_size);
+            continue;
+        }
+
+        if current_size + size > max_size {
+            packets.push(current);
+            current = rpc_proto::RPC::new();
+            current_size = 0;
+        }
+
+        current.mut_subscriptions().push(subscription);
+        current_size += size;
+    }
+
+    if packets.is_empty() || !current.get_subscriptions().is_empty() {
+        packets.push(current);
+    }
+
+    packets
+}
+
 // Shortcut function that hashes a value.
 #[inline]
 fn hash<V: Hash>(value: V) -> u64 {
@@ -659,3 +927,227 @@ fn hash<V: Hash>(value: V) -> u64 {
     value.hash(&mut h);
     h.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::sync::mpsc;
+    use futures::{future, Async, Future, Stream};
+    use libp2p_core::PeerId;
+    use multiaddr::Multiaddr;
+    use parking_lot::RwLock;
+    use protobuf;
+    use protobuf::Message as ProtobufMessage;
+    use rpc_proto;
+    use std::iter;
+    use {handle_packet_received, FloodSubConfig, FloodSubController, FloodSubReceiver};
+    use {FloodSubUpgrade, PacketError, PublishError};
+    use {RemoteInfo, Topic, TopicBuilder, TopicHash, MAX_TOPIC_HASH_LEN};
+
+    fn peer_id(byte: u8) -> PeerId {
+        let bytes = vec![18, 32].into_iter().chain(iter::repeat(byte).take(32)).collect();
+        PeerId::from_bytes(bytes).unwrap()
+    }
+
+    fn remote_addr() -> Multiaddr {
+        "/ip4/1.2.3.4/tcp/5000".parse().unwrap()
+    }
+
+    // Builds a node subscribed to `topic`, and connected to a remote at `remote_addr()` which is
+    // subscribed to `topic` as well. Also returns the packets sent to this remote.
+    fn node(
+        topic: &Topic,
+        config: FloodSubConfig,
+    ) -> (FloodSubUpgrade, FloodSubReceiver, mpsc::UnboundedReceiver<BytesMut>) {
+        let (upgrade, receiver) = FloodSubUpgrade::with_config(peer_id(1), config);
+        FloodSubController::new(&upgrade).subscribe(topic);
+        let remote = add_remote(&upgrade, remote_addr(), topic);
+        (upgrade, receiver, remote)
+    }
+
+    // Adds to `upgrade` a remote at `addr` subscribed to `topic`, and returns the packets sent to
+    // this remote.
+    fn add_remote(
+        upgrade: &FloodSubUpgrade,
+        addr: Multiaddr,
+        topic: &Topic,
+    ) -> mpsc::UnboundedReceiver<BytesMut> {
+        let (tx, rx) = mpsc::unbounded();
+        upgrade.inner.remote_connections.write().insert(addr, RemoteInfo {
+            sender: tx,
+            subscribed_topics: RwLock::new(iter::once(topic.hash().clone()).collect()),
+        });
+        rx
+    }
+
+    // Decodes the packets sent to a remote, and returns the sequence numbers of the messages they
+    // contain, per packet.
+    fn sent_seq_nos(remote: &mut mpsc::UnboundedReceiver<BytesMut>) -> Vec<Vec<Vec<u8>>> {
+        drain(remote)
+            .into_iter()
+            .map(|packet| {
+                protobuf::parse_from_bytes::<rpc_proto::RPC>(&packet)
+                    .unwrap()
+                    .get_publish()
+                    .iter()
+                    .map(|m| m.get_seqno().to_vec())
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Builds a message published by a remote.
+    fn message(topics: &[&str], seq_no: u64) -> rpc_proto::Message {
+        let mut msg = rpc_proto::Message::new();
+        msg.set_from(peer_id(2).into_bytes());
+        msg.set_seqno(seq_no.to_string().into_bytes());
+        msg.set_data(vec![1, 2, 3]);
+        msg.set_topicIDs(topics.iter().map(|t| t.to_string()).collect());
+        msg
+    }
+
+    // Returns all the items that are immediately available on `stream`.
+    fn drain<S: Stream>(stream: &mut S) -> Vec<S::Item> {
+        future::poll_fn(|| -> Result<_, ()> {
+            let mut items = Vec::new();
+            while let Ok(Async::Ready(Some(item))) = stream.poll() {
+                items.push(item);
+            }
+            Ok(Async::Ready(items))
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn invalid_topic_ids_are_rejected() {
+        let topic = TopicBuilder::new("foo").build().unwrap();
+        let valid = topic.hash().clone().into_string();
+        let too_long = vec!["a"; MAX_TOPIC_HASH_LEN + 1].concat();
+
+        for invalid in &[String::new(), too_long] {
+            let mut in_subscription = rpc_proto::RPC::new();
+            let mut subscription = rpc_proto::RPC_SubOpts::new();
+            subscription.set_subscribe(true);
+            subscription.set_topicid(invalid.clone());
+            in_subscription.mut_subscriptions().push(subscription);
+            in_subscription.mut_publish().push(message(&[&valid], 0));
+
+            let mut in_message = rpc_proto::RPC::new();
+            in_message.mut_publish().push(message(&[&valid, invalid], 0));
+
+            for rpc in vec![in_subscription, in_message] {
+                let (upgrade, mut receiver, mut remote) = node(&topic, FloodSubConfig::new());
+                let bytes = rpc.write_to_bytes().unwrap()[..].into();
+                match handle_packet_received(bytes, upgrade.inner.clone(), &remote_addr()) {
+                    Err(PacketError::InvalidTopicId { len }) => assert_eq!(len, invalid.len()),
+                    _ => panic!(),
+                }
+
+                assert!(drain(&mut receiver).is_empty());
+                assert!(drain(&mut remote).is_empty());
+                let remotes = upgrade.inner.remote_connections.read();
+                let subscribed = remotes[&remote_addr()].subscribed_topics.read();
+                assert_eq!(subscribed.len(), 1);
+                assert!(subscribed.contains(&TopicHash::from_raw(valid.clone())));
+            }
+        }
+    }
+
+    #[test]
+    fn messages_beyond_limit_are_dropped() {
+        let topic = TopicBuilder::new("foo").build().unwrap();
+        let valid = topic.hash().clone().into_string();
+        let (upgrade, mut receiver, mut remote) = node(&topic, {
+            let mut config = FloodSubConfig::new();
+            config.max_messages_per_rpc(3);
+            config
+        });
+
+        let mut rpc = rpc_proto::RPC::new();
+        for seq_no in 0..5 {
+            rpc.mut_publish().push(message(&[&valid], seq_no));
+        }
+        let bytes = rpc.write_to_bytes().unwrap()[..].into();
+        let result = handle_packet_received(bytes, upgrade.inner.clone(), &remote_addr());
+        assert!(result.is_ok());
+
+        assert_eq!(drain(&mut receiver).len(), 3);
+        let expected = vec![b"0".to_vec(), b"1".to_vec(), b"2".to_vec()];
+        assert_eq!(sent_seq_nos(&mut remote), vec![expected]);
+    }
+
+    #[test]
+    fn messages_forwarded_only_to_subscribed_remotes() {
+        let topic_a = TopicBuilder::new("a").build().unwrap();
+        let topic_b = TopicBuilder::new("b").build().unwrap();
+        let topic_c = TopicBuilder::new("c").build().unwrap();
+        let (upgrade, _receiver, mut remote_a) = node(&topic_a, FloodSubConfig::new());
+        let mut remote_b = add_remote(&upgrade, "/ip4/1.2.3.4/tcp/5001".parse().unwrap(), &topic_b);
+        let mut remote_c = add_remote(&upgrade, "/ip4/1.2.3.4/tcp/5002".parse().unwrap(), &topic_c);
+
+        let hash_a = topic_a.hash().clone().into_string();
+        let hash_b = topic_b.hash().clone().into_string();
+        let mut rpc = rpc_proto::RPC::new();
+        rpc.mut_publish().push(message(&[&hash_a], 0));
+        rpc.mut_publish().push(message(&[&hash_b], 1));
+        rpc.mut_publish().push(message(&[&hash_a, &hash_b], 2));
+        let bytes = rpc.write_to_bytes().unwrap()[..].into();
+        let result = handle_packet_received(bytes, upgrade.inner.clone(), &remote_addr());
+        assert!(result.is_ok());
+
+        assert_eq!(sent_seq_nos(&mut remote_a), vec![vec![b"0".to_vec(), b"2".to_vec()]]);
+        assert_eq!(sent_seq_nos(&mut remote_b), vec![vec![b"1".to_vec(), b"2".to_vec()]]);
+        assert!(drain(&mut remote_c).is_empty());
+    }
+
+    #[test]
+    fn publish_fails_if_message_too_large() {
+        let topic = TopicBuilder::new("foo").build().unwrap();
+        let mut config = FloodSubConfig::new();
+        config.max_transmit_size(1024);
+        let (upgrade, _receiver, mut remote) = node(&topic, config);
+        let controller = FloodSubController::new(&upgrade);
+
+        match controller.publish(&topic, vec![0; 1024]) {
+            Err(PublishError::MessageTooLarge { size, max }) => {
+                assert!(size > 1024);
+                assert_eq!(max, 1024);
+            }
+            _ => panic!(),
+        }
+        assert!(drain(&mut remote).is_empty());
+
+        // The failed attempt must not have used a sequence number.
+        assert!(controller.publish(&topic, vec![0; 16]).is_ok());
+        let sent = drain(&mut remote);
+        assert_eq!(sent.len(), 1);
+        let sent = protobuf::parse_from_bytes::<rpc_proto::RPC>(&sent[0]).unwrap();
+        assert_eq!(sent.get_publish()[0].get_seqno(), &[0; 8][..]);
+    }
+
+    #[test]
+    fn subscriptions_split_to_fit_max_transmit_size() {
+        let topic = TopicBuilder::new("foo").build().unwrap();
+        let mut config = FloodSubConfig::new();
+        config.max_transmit_size(128);
+        let (upgrade, _receiver, mut remote) = node(&topic, config);
+
+        let topics = (0..20)
+            .map(|n| TopicBuilder::new(format!("topic {}", n)).build().unwrap())
+            .collect::<Vec<_>>();
+        FloodSubController::new(&upgrade).subscribe_many(&topics);
+
+        let packets = drain(&mut remote);
+        assert!(packets.len() > 1);
+        let mut announced = Vec::new();
+        for packet in packets {
+            assert!(packet.len() <= 128);
+            let rpc = protobuf::parse_from_bytes::<rpc_proto::RPC>(&packet).unwrap();
+            for subscription in rpc.get_subscriptions() {
+                assert!(subscription.get_subscribe());
+                announced.push(TopicHash::from_raw(subscription.get_topicid().to_owned()));
+            }
+        }
+        let expected = topics.iter().map(|t| t.hash().clone()).collect::<Vec<_>>();
+        assert_eq!(announced, expected);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use fnv::FnvHashSet;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Set of values that have been seen recently.
+///
+/// Each value is forgotten once `ttl` has elapsed since its insertion. If the cache is full, the
+/// oldest value is forgotten early in order to make room for a new one.
+#[derive(Debug)]
+pub struct DuplicateCache<T> {
+    // Values currently in the cache.
+    values: FnvHashSet<T>,
+    // The same values, with their expiration time, from the oldest to the most recent.
+    expirations: VecDeque<(Instant, T)>,
+    // Duration after which an inserted value is removed.
+    ttl: Duration,
+    // Maximum number of values in the cache.
+    capacity: usize,
+}
+
+impl<T> DuplicateCache<T>
+where
+    T: Hash + Eq + Clone,
+{
+    /// Creates a new empty cache.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn new(ttl: Duration, capacity: usize) -> DuplicateCache<T> {
+        assert_ne!(capacity, 0);
+
+        DuplicateCache {
+            values: FnvHashSet::default(),
+            expirations: VecDeque::new(),
+            ttl: ttl,
+            capacity: capacity,
+        }
+    }
+
+    /// Inserts a value in the cache. Returns `false` if the value was already present, in which
+    /// case its expiration time is left untouched.
+    #[inline]
+    pub fn insert(&mut self, value: T) -> bool {
+        self.insert_at(value, Instant::now())
+    }
+
+    /// Same as `insert`, but considers that the current time is `now`.
+    fn insert_at(&mut self, value: T, now: Instant) -> bool {
+        self.remove_expired(now);
+
+        if self.values.contains(&value) {
+            return false;
+        }
+
+        if self.values.len() >= self.capacity {
+            if let Some((_, oldest)) = self.expirations.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+
+        self.values.insert(value.clone());
+        self.expirations.push_back((now + self.ttl, value));
+        true
+    }
+
+    // Removes from the cache all the values that have expired at `now`.
+    fn remove_expired(&mut self, now: Instant) {
+        while self.expirations.front().map(|&(exp, _)| exp <= now).unwrap_or(false) {
+            let (_, value) = self.expirations.pop_front().expect("front() returned Some");
+            self.values.remove(&value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use time_cache::DuplicateCache;
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut cache = DuplicateCache::new(Duration::from_secs(10), 16);
+        assert!(cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(!cache.insert(1));
+        assert_eq!(cache.values.len(), 2);
+    }
+
+    #[test]
+    fn values_expire() {
+        let mut cache = DuplicateCache::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        assert!(cache.insert_at(1, now));
+        assert!(cache.insert_at(2, now + Duration::from_secs(5)));
+        assert!(!cache.insert_at(1, now + Duration::from_secs(9)));
+        assert!(cache.insert_at(1, now + Duration::from_secs(10)));
+        assert!(!cache.insert_at(2, now + Duration::from_secs(14)));
+        assert_eq!(cache.values.len(), 2);
+    }
+
+    #[test]
+    fn duplicate_does_not_refresh_expiration() {
+        let mut cache = DuplicateCache::new(Duration::from_secs(10), 16);
+        let now = Instant::now();
+        assert!(cache.insert_at(1, now));
+        assert!(!cache.insert_at(1, now + Duration::from_secs(8)));
+        assert!(cache.insert_at(1, now + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn oldest_evicted_when_full() {
+        let mut cache = DuplicateCache::new(Duration::from_secs(10), 3);
+        let now = Instant::now();
+        for n in 0..4 {
+            assert!(cache.insert_at(n, now));
+        }
+        assert_eq!(cache.values.len(), 3);
+        assert!(!cache.insert_at(3, now));
+        assert!(cache.insert_at(0, now));
+    }
+}
@@ -21,6 +21,10 @@
 use bs58;
 use protobuf::Message;
 use rpc_proto;
+use std::{error, fmt};
+
+/// Maximum length in bytes of a topic hash. Remotes reject packets containing a longer one.
+pub const MAX_TOPIC_HASH_LEN: usize = 1024;
 
 /// Represents the hash of a topic.
 ///
@@ -77,16 +81,76 @@ impl TopicBuilder {
     }
 
     /// Turns the builder into an actual `Topic`.
-    pub fn build(self) -> Topic {
+    ///
+    /// Returns an error if the hash of the topic would be longer than `MAX_TOPIC_HASH_LEN`, as
+    /// remotes would then reject the packets referring to it.
+    pub fn build(self) -> Result<Topic, TopicBuildError> {
         let bytes = self.builder
             .write_to_bytes()
             .expect("protobuf message is always valid");
-        let hash = TopicHash {
-            hash: bs58::encode(&bytes).into_string(),
-        };
-        Topic {
+        let hash = bs58::encode(&bytes).into_string();
+        if !is_valid_topic_hash(&hash) {
+            return Err(TopicBuildError::HashTooLong {
+                len: hash.len(),
+                max: MAX_TOPIC_HASH_LEN,
+            });
+        }
+
+        Ok(Topic {
             descriptor: self.builder,
-            hash: hash,
+            hash: TopicHash { hash: hash },
+        })
+    }
+}
+
+/// Error that can happen when building a `Topic`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicBuildError {
+    /// The hash of the topic is longer than `MAX_TOPIC_HASH_LEN`.
+    HashTooLong {
+        /// Length of the hash in bytes.
+        len: usize,
+        /// Maximum length allowed in bytes.
+        max: usize,
+    },
+}
+
+impl error::Error for TopicBuildError {}
+
+impl fmt::Display for TopicBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TopicBuildError::HashTooLong { len, max } =>
+                write!(f, "topic hash of {} bytes exceeds the maximum of {} bytes", len, max),
+        }
+    }
+}
+
+/// Returns true if `hash` is acceptable as the hash of a topic, either built locally or received
+/// from a remote.
+#[inline]
+pub fn is_valid_topic_hash(hash: &str) -> bool {
+    !hash.is_empty() && hash.len() <= MAX_TOPIC_HASH_LEN
+}
+
+#[cfg(test)]
+mod tests {
+    use topic::{TopicBuildError, TopicBuilder, MAX_TOPIC_HASH_LEN};
+
+    #[test]
+    fn build_short_topic() {
+        let topic = TopicBuilder::new("foo").build().unwrap();
+        assert!(!topic.hash().clone().into_string().is_empty());
+    }
+
+    #[test]
+    fn build_fails_if_hash_too_long() {
+        match TopicBuilder::new(vec!["a"; MAX_TOPIC_HASH_LEN].concat()).build() {
+            Err(TopicBuildError::HashTooLong { len, max }) => {
+                assert!(len > MAX_TOPIC_HASH_LEN);
+                assert_eq!(max, MAX_TOPIC_HASH_LEN);
+            }
+            _ => panic!(),
         }
     }
 }